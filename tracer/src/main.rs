@@ -1,5 +1,5 @@
 use libbpf_rs::skel::{OpenSkel, Skel, SkelBuilder};
-use libbpf_rs::{MapFlags, PerfBufferBuilder, UprobeOpts};
+use libbpf_rs::{MapFlags, RingBufferBuilder, UprobeOpts, UsdtOpts};
 use std::cell::RefCell;
 use std::os::unix::ffi::OsStrExt;
 use byteorder::ByteOrder;
@@ -13,8 +13,20 @@ use uprobe::*;
 thread_local! {
     static SKEL: RefCell<Option<UprobeSkel<'static>>> = RefCell::new(None);
 }
-fn handle_lost_events(cpu: i32, count: u64) {
-    eprintln!("Lost {count} events on CPU {cpu}");
+/// Event-type discriminators written into [`EventHeader::kind`]. Every BPF
+/// program stamps its record with one of these so the consumer can pick the
+/// right decoder instead of guessing from the delivering CPU.
+const EVENT_LOG: u32 = 0;
+const EVENT_QUEUE_DEPTH: u32 = 1;
+const EVENT_STACK_FRAME: u32 = 2;
+const EVENT_USDT: u32 = 3;
+/// Self-describing header prefixed to every record pushed through the buffer:
+/// a `kind` discriminator followed by the length of the payload that trails it.
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Copy, Clone)]
+struct EventHeader {
+    kind: u32,
+    len: u32,
 }
 #[repr(C)]
 #[derive(bytemuck::Pod, bytemuck::Zeroable, Copy, Clone)]
@@ -22,52 +34,340 @@ struct Event {
     event_head: u64,
     queue_len: u64,
 }
-fn handle_event(cpu: i32, data: &[u8]) {
-    if cpu == 0 {
-        eprintln!("{}", String::from_utf8_lossy(data));
-    } else if cpu == 1 {
-        let event: Event = bytemuck::pod_read_unaligned(&data[..16]);
-        eprintln!("queue_len: {}, event_head: {:#x}", event.queue_len, event.event_head);
-    }
+/// A single unwound stack-trace frame captured by a probe handler.
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Copy, Clone)]
+struct StackFrame {
+    ip: u64,
+    symbol: [u8; 64],
 }
-fn main() {
-    let mut builder = UprobeSkelBuilder::default();
-    let picom_path = std::env::args().nth(1).unwrap();
-    let interceptor_path = std::env::args().nth(2).unwrap();
-    let data = std::fs::read(&picom_path).unwrap();
-    let file = object::read::elf::ElfFile64::<'_, object::NativeEndian, _>::parse(&*data).unwrap();
+/// A record emitted by a `bpf_usdt_readarg`-based handler attached to a
+/// `provider:probe` tracepoint compiled into picom. The BPF side fills the
+/// timestamp with `bpf_ktime_get_ns()` and copies the probe's source location
+/// and arguments before pushing this, behind an [`EVENT_USDT`] header, through
+/// the ring buffer.
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Copy, Clone)]
+struct Trace {
+    timestamp: u64,
+    probe_name: [u8; 32],
+    file: [u8; 128],
+    line: u32,
+    column: u32,
+    args: [u64; 4],
+}
+/// The USDT tracepoints picom is compiled with, as `provider:probe` pairs. The
+/// probe bodies are inlined at the call site and carry no cost until a handler
+/// is attached to them here.
+const USDT_PROBES: &[(&str, &str)] = &[
+    ("picom", "render_start"),
+    ("picom", "frame_presented"),
+    ("picom", "damage_accumulated"),
+];
+fn cstr(bytes: &[u8]) -> std::borrow::Cow<'_, str> {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end])
+}
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// The dynamic-section strings that steer library resolution, kept as raw
+/// bytes so non-UTF-8 install paths survive untouched.
+#[derive(Default)]
+struct DynamicInfo {
+    rpath: Option<Vec<u8>>,
+    runpath: Option<Vec<u8>>,
+    needed: Vec<Vec<u8>>,
+}
+
+/// Parse the `DT_RPATH`, `DT_RUNPATH` and `DT_NEEDED` entries out of an ELF
+/// binary's dynamic section.
+fn parse_dynamic(data: &[u8]) -> DynamicInfo {
+    let mut info = DynamicInfo::default();
+    let file = object::read::elf::ElfFile64::<'_, object::NativeEndian, _>::parse(data).unwrap();
     let header = file.raw_header();
-    let sections = header.sections(object::NativeEndian, &*data).unwrap();
-    let (dyanmic, dynamic_index) = sections.dynamic(object::NativeEndian, &*data).unwrap().unwrap();
-    let strings = sections.strings(object::NativeEndian, &*data, dynamic_index).unwrap();
-    let mut runpath = None;
-    let mut libc_name = None;
-    for d in dyanmic {
-        if d.is_string(object::NativeEndian) {
-            let s = d.string(object::NativeEndian, strings).unwrap();
-            let tag = d.d_tag(object::NativeEndian);
-            if tag == object::elf::DT_RUNPATH as u64 {
-                runpath = Some(s.to_vec());
+    let sections = header.sections(object::NativeEndian, data).unwrap();
+    let (dynamic, dynamic_index) =
+        sections.dynamic(object::NativeEndian, data).unwrap().unwrap();
+    let strings = sections.strings(object::NativeEndian, data, dynamic_index).unwrap();
+    for d in dynamic {
+        if !d.is_string(object::NativeEndian) {
+            continue;
+        }
+        let s = d.string(object::NativeEndian, strings).unwrap();
+        match d.d_tag(object::NativeEndian) as u32 {
+            object::elf::DT_RPATH => info.rpath = Some(s.to_vec()),
+            object::elf::DT_RUNPATH => info.runpath = Some(s.to_vec()),
+            object::elf::DT_NEEDED => info.needed.push(s.to_vec()),
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Expand the dynamic-string tokens ld.so understands. Only `$ORIGIN` (and its
+/// `${ORIGIN}` spelling) matters for our search paths; it stands for the
+/// directory containing the object being loaded.
+fn expand_origin(path: &[u8], origin: &OsStr) -> PathBuf {
+    let mut out = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let rest = &path[i..];
+        if rest.starts_with(b"$ORIGIN") {
+            out.extend_from_slice(origin.as_bytes());
+            i += b"$ORIGIN".len();
+        } else if rest.starts_with(b"${ORIGIN}") {
+            out.extend_from_slice(origin.as_bytes());
+            i += b"${ORIGIN}".len();
+        } else {
+            out.push(path[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(out))
+}
+
+/// Yield the directories in a colon-separated search list, expanding `$ORIGIN`
+/// against `origin`.
+fn search_dirs(list: &[u8], origin: &OsStr) -> Vec<PathBuf> {
+    list.split(|c| *c == b':')
+        .filter(|p| !p.is_empty())
+        .map(|p| expand_origin(p, origin))
+        .collect()
+}
+
+/// Look a shared object up in `/etc/ld.so.cache`, returning the stored path of
+/// the first entry whose key (the library name) equals `name`. Understands the modern
+/// `glibc-ld.so.cache1.1` format, skipping a leading old-format cache if the
+/// file carries both.
+fn lookup_cache(name: &OsStr) -> Option<PathBuf> {
+    const OLD_MAGIC: &[u8] = b"ld.so-1.7.0\0";
+    const NEW_MAGIC: &[u8] = b"glibc-ld.so.cache1.1";
+    let cache = std::fs::read("/etc/ld.so.cache").ok()?;
+    let mut base = 0;
+    // An old-format cache may precede the new one; step over it if present.
+    if cache.get(..OLD_MAGIC.len()) == Some(OLD_MAGIC) {
+        let nlibs = u32::from_ne_bytes(cache.get(12..16)?.try_into().ok()?) as usize;
+        let strings_start = 16 + nlibs * 12;
+        // The string table length isn't recorded in the old header, so the new
+        // cache is 8-byte aligned right after the old magic+entries+strings —
+        // scan forward for the new magic instead of trusting an offset.
+        base = cache[strings_start..]
+            .windows(NEW_MAGIC.len())
+            .position(|w| w == NEW_MAGIC)
+            .map(|p| strings_start + p)?;
+    }
+    let new = cache.get(base..)?;
+    if new.get(..NEW_MAGIC.len()) != Some(NEW_MAGIC) {
+        return None;
+    }
+    let nlibs = u32::from_ne_bytes(new.get(20..24)?.try_into().ok()?) as usize;
+    const HEADER: usize = 48;
+    const ENTRY: usize = 24;
+    let read_str = |off: usize| -> Option<&[u8]> {
+        let abs = base + off;
+        let end = cache[abs..].iter().position(|b| *b == 0)? + abs;
+        Some(&cache[abs..end])
+    };
+    for i in 0..nlibs {
+        let entry = HEADER + i * ENTRY;
+        let key = u32::from_ne_bytes(new.get(entry + 4..entry + 8)?.try_into().ok()?) as usize;
+        let value = u32::from_ne_bytes(new.get(entry + 8..entry + 12)?.try_into().ok()?) as usize;
+        if read_str(key).map(OsStr::from_bytes) == Some(name) {
+            return Some(PathBuf::from(std::ffi::OsString::from_vec(read_str(value)?.to_vec())));
+        }
+    }
+    None
+}
+
+/// The trusted default directories ld.so searches last, including the common
+/// Debian/Ubuntu multiarch layout for the host architecture.
+fn default_dirs() -> Vec<PathBuf> {
+    // Map the Rust arch name to the GNU multiarch triplet ld.so searches; these
+    // differ from a plain `<arch>-linux-gnu` for 32-bit arm and x86.
+    let triplet = match std::env::consts::ARCH {
+        "x86" => "i386-linux-gnu".to_string(),
+        "arm" => "arm-linux-gnueabihf".to_string(),
+        arch => format!("{arch}-linux-gnu"),
+    };
+    vec![
+        PathBuf::from(format!("/lib/{triplet}")),
+        PathBuf::from(format!("/usr/lib/{triplet}")),
+        PathBuf::from("/lib"),
+        PathBuf::from("/usr/lib"),
+        PathBuf::from("/lib64"),
+        PathBuf::from("/usr/lib64"),
+    ]
+}
+
+/// Resolve a `DT_NEEDED` entry to its on-disk path using the real ld.so search
+/// order: `DT_RPATH` (only when there is no `DT_RUNPATH`), then
+/// `LD_LIBRARY_PATH`, then `DT_RUNPATH`, then `/etc/ld.so.cache`, and finally
+/// the trusted default directories. `$ORIGIN` is expanded relative to the
+/// directory holding `elf_path`.
+fn resolve_library(elf_path: &Path, info: &DynamicInfo, name: &OsStr) -> Option<PathBuf> {
+    let origin = elf_path.parent().unwrap_or_else(|| Path::new(".")).as_os_str();
+    let mut dirs = Vec::new();
+    if info.runpath.is_none() {
+        if let Some(rpath) = &info.rpath {
+            dirs.extend(search_dirs(rpath, origin));
+        }
+    }
+    if let Some(ld_library_path) = std::env::var_os("LD_LIBRARY_PATH") {
+        dirs.extend(search_dirs(ld_library_path.as_bytes(), origin));
+    }
+    if let Some(runpath) = &info.runpath {
+        dirs.extend(search_dirs(runpath, origin));
+    }
+    for dir in &dirs {
+        let candidate = dir.join(name);
+        if candidate.exists() {
+            return Some(candidate);
+        }
+    }
+    if let Some(cached) = lookup_cache(name) {
+        if cached.exists() {
+            return Some(cached);
+        }
+    }
+    for dir in default_dirs() {
+        let candidate = dir.join(name);
+        if candidate.exists() {
+            return Some(candidate);
+        }
+    }
+    None
+}
+/// Decode a fixed-size `#[repr(C)]` record out of the front of a payload,
+/// reporting and dropping the event if the payload is too short rather than
+/// indexing past its end (recorded/fixture data is not trusted).
+fn decode<T: bytemuck::Pod>(payload: &[u8], kind: u32) -> Option<T> {
+    match payload.get(..std::mem::size_of::<T>()) {
+        Some(bytes) => Some(bytemuck::pod_read_unaligned(bytes)),
+        None => {
+            eprintln!(
+                "truncated event kind {kind}: need {} bytes, have {}",
+                std::mem::size_of::<T>(),
+                payload.len(),
+            );
+            None
+        }
+    }
+}
+fn handle_event(data: &[u8]) {
+    let Some(head_bytes) = data.get(..std::mem::size_of::<EventHeader>()) else {
+        eprintln!("truncated event header: {} bytes", data.len());
+        return;
+    };
+    let header: EventHeader = bytemuck::pod_read_unaligned(head_bytes);
+    let Some(payload) = data
+        .get(std::mem::size_of::<EventHeader>()..)
+        .and_then(|rest| rest.get(..header.len as usize))
+    else {
+        eprintln!(
+            "event kind {} claims {} payload bytes, only {} present",
+            header.kind,
+            header.len,
+            data.len().saturating_sub(std::mem::size_of::<EventHeader>()),
+        );
+        return;
+    };
+    match header.kind {
+        EVENT_LOG => {
+            eprintln!("{}", String::from_utf8_lossy(payload));
+        }
+        EVENT_QUEUE_DEPTH => {
+            if let Some(event) = decode::<Event>(payload, header.kind) {
+                eprintln!("queue_len: {}, event_head: {:#x}", event.queue_len, event.event_head);
+            }
+        }
+        EVENT_STACK_FRAME => {
+            if let Some(frame) = decode::<StackFrame>(payload, header.kind) {
+                eprintln!("#{:#x} {}", frame.ip, cstr(&frame.symbol));
             }
-            if tag == object::elf::DT_NEEDED as u64 && s.starts_with(b"libc.so") {
-                libc_name = Some(s.to_vec())
+        }
+        EVENT_USDT => {
+            if let Some(trace) = decode::<Trace>(payload, header.kind) {
+                eprintln!(
+                    "[{}] {} ({}:{}:{}) args={:?}",
+                    trace.timestamp,
+                    cstr(&trace.probe_name),
+                    cstr(&trace.file),
+                    trace.line,
+                    trace.column,
+                    trace.args,
+                );
             }
-            eprintln!("{} {}", tag, String::from_utf8_lossy(s));
         }
+        other => eprintln!("unknown event kind {other} ({} bytes)", header.len),
     }
-    let runpath = runpath.unwrap_or(b"/usr/lib".to_vec());
-    let libc_name = libc_name.unwrap();
-    let libc_name = std::ffi::OsStr::from_bytes(&libc_name);
-    let mut libc_path = None;
-    for p in runpath.split(|ch| *ch == b':') {
-        let p = std::ffi::OsStr::from_bytes(p);
-        let p = std::path::Path::new(p);
-        let p = p.join(libc_name);
-        if p.exists() {
-            libc_path = Some(p);
+}
+/// Append one raw event record (header + payload) to a capture log, prefixed
+/// with its native-endian `u32` length so [`replay`] can frame it back out.
+fn write_record(writer: &RefCell<std::io::BufWriter<std::fs::File>>, data: &[u8]) {
+    use std::io::Write;
+    let mut writer = writer.borrow_mut();
+    writer.write_all(&(data.len() as u32).to_ne_bytes()).unwrap();
+    writer.write_all(data).unwrap();
+    writer.flush().unwrap();
+}
+
+/// Re-run the live [`handle_event`] decoder over a log written by `--record`,
+/// without attaching any probes, so a saved trace can be decoded offline.
+fn replay(path: &OsStr) {
+    let data = std::fs::read(path).unwrap();
+    let mut off = 0;
+    while off + 4 <= data.len() {
+        let len = u32::from_ne_bytes(data[off..off + 4].try_into().unwrap()) as usize;
+        off += 4;
+        if off + len > data.len() {
+            eprintln!(
+                "truncated record frame: want {len} bytes, only {} remain",
+                data.len() - off,
+            );
+            break;
         }
+        handle_event(&data[off..off + len]);
+        off += len;
     }
-    let libc_path = libc_path.unwrap();
+}
+
+fn main() {
+    let mut builder = UprobeSkelBuilder::default();
+    let mut positional = Vec::new();
+    let mut record_path = None;
+    let mut replay_path = None;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--record" {
+            record_path = Some(args.next().expect("--record requires a path"));
+        } else if arg == "--replay" {
+            replay_path = Some(args.next().expect("--replay requires a path"));
+        } else {
+            positional.push(arg);
+        }
+    }
+    if let Some(path) = replay_path {
+        replay(&path);
+        return;
+    }
+    let picom_path = positional.first().expect("missing picom binary path").clone();
+    let interceptor_path = positional.get(1).expect("missing interceptor library path").clone();
+    let recorder = record_path.map(|path| {
+        RefCell::new(std::io::BufWriter::new(std::fs::File::create(path).unwrap()))
+    });
+    let data = std::fs::read(&picom_path).unwrap();
+    let info = parse_dynamic(&data);
+    let libc_name = info
+        .needed
+        .iter()
+        .find(|n| n.starts_with(b"libc.so"))
+        .expect("picom does not link against libc");
+    let libc_name = OsStr::from_bytes(libc_name);
+    let libc_path = resolve_library(Path::new(&picom_path), &info, libc_name)
+        .unwrap_or_else(|| panic!("could not resolve {}", libc_name.to_string_lossy()));
     eprintln!("{}", libc_path.to_string_lossy());
     builder.obj_builder.debug(true);
 
@@ -92,15 +392,58 @@ fn main() {
         func_name: "epoll_wait".to_string(),
         ..Default::default()
     }).unwrap();
+    let usdt_prog = obj.prog_mut("usdt_probe").unwrap();
+    let mut _usdt_links = Vec::new();
+    for (provider, name) in USDT_PROBES {
+        _usdt_links.push(usdt_prog.attach_usdt_with_opts(
+            -1,
+            &picom_path,
+            provider,
+            name,
+            UsdtOpts::default(),
+        ).unwrap());
+    }
 
-    let perf = PerfBufferBuilder::new(skel.maps_mut().events())
-        .sample_cb(handle_event)
-        .lost_cb(handle_lost_events)
-        .build().unwrap();
+    let mut ring_builder = RingBufferBuilder::new();
+    ring_builder
+        .add(skel.maps().events(), |data| {
+            if let Some(recorder) = &recorder {
+                write_record(recorder, data);
+            }
+            handle_event(data);
+            0
+        })
+        .unwrap();
+    let ring = ring_builder.build().unwrap();
 
     SKEL.with_borrow_mut(|s| *s = Some(skel));
 
+    // A single shared ring buffer has no per-CPU lost callback; the BPF side
+    // tallies failed `bpf_ringbuf_reserve`s into the `dropped` counter, which
+    // we sample and report as deltas.
+    let mut reported_drops = 0u64;
     loop {
-        perf.poll(std::time::Duration::from_millis(100)).unwrap();
+        ring.poll(std::time::Duration::from_millis(100)).unwrap();
+        let drops = read_dropped();
+        if drops > reported_drops {
+            eprintln!("Lost {} events (ring buffer full)", drops - reported_drops);
+            reported_drops = drops;
+        }
     }
 }
+
+/// Read the BPF-side dropped-event counter stored in the single-slot `dropped`
+/// array map.
+fn read_dropped() -> u64 {
+    SKEL.with_borrow(|s| {
+        let skel = s.as_ref().unwrap();
+        let value = skel
+            .maps()
+            .dropped()
+            .lookup(&0u32.to_ne_bytes(), MapFlags::ANY)
+            .unwrap();
+        value
+            .and_then(|v| v.get(..8).map(|b| u64::from_ne_bytes(b.try_into().unwrap())))
+            .unwrap_or(0)
+    })
+}